@@ -1,13 +1,64 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use async_graphql::*;
 use dashmap::DashMap;
 use serde::Serialize;
-use tokio::sync::{mpsc::Sender, RwLock};
+use tokio::sync::mpsc::Sender;
 
-#[derive(Default)]
+use crate::db::Database;
+use crate::metrics::Metrics;
+use crate::room_actor::RoomHandle;
+
+/// Identifies one live `server_messages` subscription; a player can hold
+/// several at once (multiple tabs/devices).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct ConnectionId(pub(crate) u64);
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+impl ConnectionId {
+    fn next() -> Self {
+        Self(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Clone)]
 pub struct Storage {
-    pub private_rooms: Arc<DashMap<String, Room>>,
+    pub private_rooms: Arc<DashMap<String, RoomHandle>>,
+    pub db: Arc<Database>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Storage {
+    /// Rehydrates persisted rooms from the DB and spawns an actor per room.
+    pub async fn connect(database_url: &str) -> Result<Self, anyhow::Error> {
+        let db = Arc::new(Database::connect(database_url).await?);
+        let metrics = Metrics::new()?;
+
+        let private_rooms = Arc::new(DashMap::new());
+        let metrics = Arc::new(metrics);
+        for room in db.load_rooms().await? {
+            metrics.rooms_active.inc();
+            metrics.players_active.add(room.users.len() as i64);
+            let room_id = room.id.clone();
+            private_rooms.insert(
+                room_id,
+                RoomHandle::spawn(room, db.clone(), private_rooms.clone(), metrics.clone()),
+            );
+        }
+
+        Ok(Self {
+            private_rooms,
+            db,
+            metrics,
+        })
+    }
 }
 
 #[derive(Serialize, SimpleObject, Clone)]
@@ -15,6 +66,9 @@ pub struct Storage {
 pub struct Room {
     pub id: String,
     pub users: Vec<LobbyPlayer>,
+    pub delay_difference_secs: u64,
+    pub media: Option<MediaItem>,
+    pub playlist: Vec<MediaItem>,
 }
 
 #[ComplexObject]
@@ -25,38 +79,75 @@ impl Room {
 }
 
 impl Room {
-    pub fn new(id: String, player: Player) -> Self {
-        Self { id, users: vec![] }
+    pub fn new(id: String, player: Player, delay_difference_secs: u64) -> Self {
+        let mut room = Self {
+            id,
+            users: vec![],
+            delay_difference_secs,
+            media: None,
+            playlist: vec![],
+        };
+        let _ = room.add_player(player);
+        room
+    }
+
+    /// A room with no members, for rehydrating before memberships replay in.
+    pub fn empty(id: String, delay_difference_secs: u64) -> Self {
+        Self {
+            id,
+            users: vec![],
+            delay_difference_secs,
+            media: None,
+            playlist: vec![],
+        }
+    }
+
+    pub fn set_media(&mut self, media: Option<MediaItem>) {
+        self.media = media;
+    }
+
+    pub fn set_playlist(&mut self, playlist: Vec<MediaItem>) {
+        self.playlist = playlist;
     }
 }
 
 impl Room {
-    pub fn add_player(&mut self, player: Player) -> Result<(), anyhow::Error> {
+    /// Returns whether a new member was actually added, as opposed to an
+    /// idempotent no-op re-join of someone already in the room.
+    pub fn add_player(&mut self, player: Player) -> Result<bool, anyhow::Error> {
         if self.users.iter().any(|p| p.player.id == player.id) {
-            Ok(())
+            Ok(false)
         } else {
             self.users.push(LobbyPlayer {
                 player,
-                send_channel: None,
+                connections: HashMap::new(),
                 state: UserState::NotReady(NotReadyData { empty: 0 }),
             });
-            Ok(())
+            Ok(true)
         }
     }
 
+    /// Independent of live connections, so a room survives every member
+    /// disconnecting at once and can still be reconnected to.
     pub fn is_empty(&self) -> bool {
-        self.users.is_empty() || self.users.iter().all(|user| user.has_channel())
+        self.users.is_empty()
+    }
+
+    /// True once every member has lost all of their connections. Used by the
+    /// room actor to schedule an idle-room reap rather than tearing the room
+    /// down immediately, so a brief disconnect still allows a reconnect.
+    pub fn is_idle(&self) -> bool {
+        !self.users.is_empty() && self.users.iter().all(|user| !user.is_connected())
     }
 
     pub fn set_player_channel(
         &mut self,
         player_id: String,
         channel: Sender<ServerResponse>,
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<ConnectionId, anyhow::Error> {
         let pl = self.users.iter_mut().find(|p| p.player.id == player_id);
         if let Some(pl) = pl {
-            pl.send_channel = Some(channel);
-            Ok(())
+            Ok(pl.add_connection(channel))
         } else {
             Err(anyhow::anyhow!("Player does not exist"))
         }
@@ -76,11 +167,17 @@ impl Room {
             .map(|lp| lp)
     }
 
-    pub fn disconnect_player(&mut self, player_id: &str) -> Result<(), anyhow::Error> {
-        log::info!("Removing player {}", player_id);
+    /// Drops a single connection for `player_id`. Returns whether the player
+    /// has no connections left (i.e. is now fully disconnected).
+    pub fn remove_connection(
+        &mut self,
+        player_id: &str,
+        connection_id: ConnectionId,
+    ) -> Result<bool, anyhow::Error> {
+        log::info!("Removing connection {:?} for player {}", connection_id, player_id);
         if let Some(player) = self.users.iter_mut().find(|p| p.player.id == player_id) {
-            player.send_channel = None;
-            Ok(())
+            player.remove_connection(connection_id);
+            Ok(!player.is_connected())
         } else {
             Err(anyhow::anyhow!("Player does not exist"))
         }
@@ -105,7 +202,7 @@ pub struct LobbyPlayer {
 
     #[serde(skip_serializing)]
     #[graphql(skip)]
-    pub send_channel: Option<Sender<ServerResponse>>,
+    pub connections: HashMap<ConnectionId, Sender<ServerResponse>>,
 
     pub state: UserState,
 }
@@ -116,7 +213,7 @@ impl LobbyPlayer {
         &self,
         _ctx: &Context<'_>,
     ) -> Result<bool, async_graphql::Error> {
-        Ok(self.send_channel.is_some())
+        Ok(self.is_connected())
     }
 }
 
@@ -147,6 +244,30 @@ pub struct ReadyData {
     pub position_secs: u64,
 }
 
+#[derive(Debug, SimpleObject, Serialize, Clone)]
+pub struct MediaItem {
+    pub name: String,
+    pub url: String,
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Debug, InputObject, Clone)]
+pub struct MediaItemInput {
+    pub name: String,
+    pub url: String,
+    pub duration_secs: Option<u64>,
+}
+
+impl From<MediaItemInput> for MediaItem {
+    fn from(input: MediaItemInput) -> Self {
+        Self {
+            name: input.name,
+            url: input.url,
+            duration_secs: input.duration_secs,
+        }
+    }
+}
+
 impl Room {
     pub fn get_players(&self) -> &[LobbyPlayer] {
         &self.users
@@ -155,27 +276,80 @@ impl Room {
         let futures = self.get_players().iter().map(|f| f.send(message.clone()));
         futures::future::join_all(futures).await;
     }
+
+    /// Same as `broadcast`, but skips `skip_connection_id` for
+    /// `skip_player_id` — their other connections still receive it.
+    pub async fn broadcast_except(
+        &self,
+        skip_player_id: &str,
+        skip_connection_id: ConnectionId,
+        message: ServerResponse,
+    ) {
+        let futures = self.get_players().iter().map(|player| {
+            let skip = if player.player.id == skip_player_id {
+                Some(skip_connection_id)
+            } else {
+                None
+            };
+            player.send_except(skip, message.clone())
+        });
+        futures::future::join_all(futures).await;
+    }
+
+    /// Delivers to `player_id` only. Returns whether it had a live connection.
+    pub async fn send_to(&self, player_id: &str, message: ServerResponse) -> bool {
+        match self.users.iter().find(|p| p.player.id == player_id) {
+            Some(lp) if lp.is_connected() => {
+                lp.send(message).await;
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 impl LobbyPlayer {
-    fn get_channel(&self) -> &Option<Sender<ServerResponse>> {
-        &self.send_channel
+    fn add_connection(&mut self, channel: Sender<ServerResponse>) -> ConnectionId {
+        let connection_id = ConnectionId::next();
+        self.connections.insert(connection_id, channel);
+        connection_id
+    }
+
+    fn remove_connection(&mut self, connection_id: ConnectionId) {
+        self.connections.remove(&connection_id);
     }
 
     async fn send(&self, message: ServerResponse) {
-        match self.get_channel() {
-            Some(channel) => match channel.send(message).await {
-                Ok(_) => {}
-                Err(_er) => {
+        let futures = self.connections.values().map(|channel| {
+            let message = message.clone();
+            async move {
+                if let Err(_er) = channel.send(message).await {
                     log::warn!("ERROR SENDING ")
                 }
-            },
-            None => {}
-        }
+            }
+        });
+        futures::future::join_all(futures).await;
+    }
+
+    /// Same as `send`, but skips `skip_connection_id` if it belongs to this player.
+    async fn send_except(&self, skip_connection_id: Option<ConnectionId>, message: ServerResponse) {
+        let futures = self
+            .connections
+            .iter()
+            .filter(|(connection_id, _)| Some(**connection_id) != skip_connection_id)
+            .map(|(_, channel)| {
+                let message = message.clone();
+                async move {
+                    if let Err(_er) = channel.send(message).await {
+                        log::warn!("ERROR SENDING ")
+                    }
+                }
+            });
+        futures::future::join_all(futures).await;
     }
 
-    fn has_channel(&self) -> bool {
-        self.get_channel().is_some()
+    fn is_connected(&self) -> bool {
+        !self.connections.is_empty()
     }
 }
 
@@ -213,6 +387,42 @@ pub struct PlayerRemoved {
 pub struct ChatMessage {
     pub player: Player,
     pub message: String,
+    pub color: Option<String>,
+}
+
+#[derive(SimpleObject, Serialize, Clone)]
+pub struct MediaChanged {
+    pub room: Room,
+}
+
+/// Sent once on subscribe, so the client can pass its own `connection_id`
+/// back into mutations that should skip echoing to this connection.
+#[derive(SimpleObject, Serialize, Clone)]
+pub struct ConnectionEstablished {
+    pub connection_id: u64,
+}
+
+/// `dialog_id` is derived from the two player ids sorted before hashing, so
+/// it's stable regardless of who sent first.
+#[derive(Debug, SimpleObject, Serialize, Clone)]
+pub struct DirectMessage {
+    pub dialog_id: String,
+    pub from: Player,
+    pub to_player_id: String,
+    pub message: String,
+}
+
+pub fn dialog_id(player_a: &str, player_b: &str) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut ids = [player_a, player_b];
+    ids.sort_unstable();
+
+    let mut hasher = DefaultHasher::new();
+    ids[0].hash(&mut hasher);
+    ids[1].hash(&mut hasher);
+    format!("{:x}", hasher.finish())
 }
 
 #[derive(Serialize, Union, Clone)]
@@ -225,4 +435,10 @@ pub enum ServerResponse {
     StatusUpdate(ReadyData),
 
     ChatMessage(ChatMessage),
+
+    MediaChanged(MediaChanged),
+
+    DirectMessage(DirectMessage),
+
+    ConnectionEstablished(ConnectionEstablished),
 }
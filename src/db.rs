@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use sqlx::{sqlite::SqlitePoolOptions, Row, SqlitePool};
+
+use crate::data::{DirectMessage, Player, ReadyData, Room, UserState};
+
+/// Durable backing store for rooms, memberships and last-known playback state.
+pub struct Database {
+    pool: SqlitePool,
+}
+
+impl Database {
+    pub async fn connect(database_url: &str) -> Result<Self, anyhow::Error> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS rooms (
+                id TEXT PRIMARY KEY,
+                delay_difference_secs INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS memberships (
+                room_id TEXT NOT NULL,
+                player_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                PRIMARY KEY (room_id, player_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ready_states (
+                room_id TEXT NOT NULL,
+                player_id TEXT NOT NULL,
+                playing INTEGER NOT NULL,
+                position_secs INTEGER NOT NULL,
+                PRIMARY KEY (room_id, player_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS undelivered_direct_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room_id TEXT NOT NULL,
+                dialog_id TEXT NOT NULL,
+                from_player_id TEXT NOT NULL,
+                from_player_name TEXT NOT NULL,
+                to_player_id TEXT NOT NULL,
+                message TEXT NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    pub async fn create_room(
+        &self,
+        room_id: &str,
+        delay_difference_secs: u64,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query("INSERT OR REPLACE INTO rooms (id, delay_difference_secs) VALUES (?, ?)")
+            .bind(room_id)
+            .bind(delay_difference_secs as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_room(&self, room_id: &str) -> Result<(), anyhow::Error> {
+        sqlx::query("DELETE FROM rooms WHERE id = ?")
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM memberships WHERE room_id = ?")
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM ready_states WHERE room_id = ?")
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM undelivered_direct_messages WHERE room_id = ?")
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn add_membership(&self, room_id: &str, player: &Player) -> Result<(), anyhow::Error> {
+        sqlx::query("INSERT OR REPLACE INTO memberships (room_id, player_id, name) VALUES (?, ?, ?)")
+            .bind(room_id)
+            .bind(&player.id)
+            .bind(&player.name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_membership(&self, room_id: &str, player_id: &str) -> Result<(), anyhow::Error> {
+        sqlx::query("DELETE FROM memberships WHERE room_id = ? AND player_id = ?")
+            .bind(room_id)
+            .bind(player_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("DELETE FROM ready_states WHERE room_id = ? AND player_id = ?")
+            .bind(room_id)
+            .bind(player_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_ready_state(
+        &self,
+        room_id: &str,
+        player_id: &str,
+        state: &ReadyData,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO ready_states (room_id, player_id, playing, position_secs)
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(room_id)
+        .bind(player_id)
+        .bind(state.playing)
+        .bind(state.position_secs as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Persists a direct message that couldn't be delivered immediately.
+    pub async fn queue_direct_message(
+        &self,
+        room_id: &str,
+        message: &DirectMessage,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query(
+            "INSERT INTO undelivered_direct_messages
+                (room_id, dialog_id, from_player_id, from_player_name, to_player_id, message)
+             VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(room_id)
+        .bind(&message.dialog_id)
+        .bind(&message.from.id)
+        .bind(&message.from.name)
+        .bind(&message.to_player_id)
+        .bind(&message.message)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Drains and deletes every direct message queued for `player_id`.
+    pub async fn take_pending_direct_messages(
+        &self,
+        room_id: &str,
+        player_id: &str,
+    ) -> Result<Vec<DirectMessage>, anyhow::Error> {
+        let rows = sqlx::query(
+            "SELECT dialog_id, from_player_id, from_player_name, message
+             FROM undelivered_direct_messages WHERE room_id = ? AND to_player_id = ?",
+        )
+        .bind(room_id)
+        .bind(player_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::with_capacity(rows.len());
+        for row in rows {
+            let dialog_id: String = row.try_get("dialog_id")?;
+            let from_player_id: String = row.try_get("from_player_id")?;
+            let from_player_name: String = row.try_get("from_player_name")?;
+            let message: String = row.try_get("message")?;
+            messages.push(DirectMessage {
+                dialog_id,
+                from: Player {
+                    id: from_player_id,
+                    name: from_player_name,
+                },
+                to_player_id: player_id.to_string(),
+                message,
+            });
+        }
+
+        sqlx::query("DELETE FROM undelivered_direct_messages WHERE room_id = ? AND to_player_id = ?")
+            .bind(room_id)
+            .bind(player_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(messages)
+    }
+
+    /// Rehydrates every persisted room with its memberships and last-known
+    /// ready state. Connections aren't persisted, so restored players have
+    /// none until they re-subscribe.
+    pub async fn load_rooms(&self) -> Result<Vec<Room>, anyhow::Error> {
+        let room_rows = sqlx::query("SELECT id, delay_difference_secs FROM rooms")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut rooms = Vec::with_capacity(room_rows.len());
+        for room_row in room_rows {
+            let room_id: String = room_row.try_get("id")?;
+            let delay_difference_secs: i64 = room_row.try_get("delay_difference_secs")?;
+
+            let ready_rows =
+                sqlx::query("SELECT player_id, playing, position_secs FROM ready_states WHERE room_id = ?")
+                    .bind(&room_id)
+                    .fetch_all(&self.pool)
+                    .await?;
+            let mut ready_states: HashMap<String, ReadyData> = HashMap::new();
+            for row in ready_rows {
+                let player_id: String = row.try_get("player_id")?;
+                let playing: bool = row.try_get("playing")?;
+                let position_secs: i64 = row.try_get("position_secs")?;
+                ready_states.insert(
+                    player_id,
+                    ReadyData {
+                        playing,
+                        position_secs: position_secs as u64,
+                    },
+                );
+            }
+
+            let mut room = Room::empty(room_id.clone(), delay_difference_secs as u64);
+            let member_rows = sqlx::query("SELECT player_id, name FROM memberships WHERE room_id = ?")
+                .bind(&room_id)
+                .fetch_all(&self.pool)
+                .await?;
+            for row in member_rows {
+                let player_id: String = row.try_get("player_id")?;
+                let name: String = row.try_get("name")?;
+                room.add_player(Player {
+                    id: player_id.clone(),
+                    name,
+                })?;
+                if let Some(ready_state) = ready_states.get(&player_id) {
+                    if let Some(lobby_player) = room.get_player_mut(&player_id) {
+                        lobby_player.state = UserState::Ready(ready_state.clone());
+                    }
+                }
+            }
+
+            rooms.push(room);
+        }
+
+        Ok(rooms)
+    }
+}
@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
     Schema,
@@ -11,12 +9,14 @@ use axum::{
     routing::get,
     Extension, Router, Server,
 };
-use dashmap::DashMap;
 use data::Storage;
 use schema::{MutationRoot, QueryRoot, Subscription};
 use tower_http::cors::{Any, CorsLayer};
 
 pub mod data;
+pub mod db;
+pub mod metrics;
+pub mod room_actor;
 pub mod schema;
 pub mod utils;
 
@@ -32,16 +32,19 @@ async fn graphql_playground() -> impl IntoResponse {
     ))
 }
 
+async fn metrics_handler(data: Extension<Storage>) -> impl IntoResponse {
+    data.metrics.render()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     pretty_env_logger::init();
-    let private_rooms = Arc::new(DashMap::new());
-    let data = Storage {
-        private_rooms: private_rooms,
-    };
+    let database_url =
+        std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://syncplay.db?mode=rwc".into());
+    let data = Storage::connect(&database_url).await?;
 
     let schema = Schema::build(QueryRoot, MutationRoot, Subscription)
-        .data(data)
+        .data(data.clone())
         .finish();
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
@@ -50,8 +53,10 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let app = Router::new()
         .route("/", get(graphql_playground).post(graphql_handler))
+        .route("/metrics", get(metrics_handler))
         .route_service("/ws", GraphQLSubscription::new(schema.clone()))
         .layer(Extension(schema))
+        .layer(Extension(data))
         .layer(cors);
 
     let port = std::env::var("PORT").unwrap_or_else(|_| "8080".into());
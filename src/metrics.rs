@@ -0,0 +1,51 @@
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+
+/// Prometheus metrics for a running server.
+pub struct Metrics {
+    registry: Registry,
+    pub rooms_active: IntGauge,
+    pub players_active: IntGauge,
+    pub chat_messages_total: IntCounter,
+    pub status_updates_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, anyhow::Error> {
+        let registry = Registry::new();
+
+        let rooms_active = IntGauge::new("sync_rooms_active", "Number of rooms currently active")?;
+        let players_active = IntGauge::new(
+            "sync_players_active",
+            "Number of players currently in a room",
+        )?;
+        let chat_messages_total =
+            IntCounter::new("sync_chat_messages_total", "Total chat messages relayed")?;
+        let status_updates_total = IntCounter::new(
+            "sync_status_updates_total",
+            "Total playback status updates broadcast",
+        )?;
+
+        registry.register(Box::new(rooms_active.clone()))?;
+        registry.register(Box::new(players_active.clone()))?;
+        registry.register(Box::new(chat_messages_total.clone()))?;
+        registry.register(Box::new(status_updates_total.clone()))?;
+
+        Ok(Self {
+            registry,
+            rooms_active,
+            players_active,
+            chat_messages_total,
+            status_updates_total,
+        })
+    }
+
+    /// Renders every registered metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metric_families = self.registry.gather();
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&metric_families, &mut buffer)
+            .unwrap_or_else(|er| log::warn!("Could not encode metrics: {}", er));
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,602 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::data::{
+    dialog_id, ChatMessage, ConnectionEstablished, ConnectionId, DirectMessage, MediaChanged,
+    MediaItem, Player, PlayerConnected, PlayerJoined, PlayerLeft, PlayerRemoved, ReadyData, Room,
+    ServerResponse, UserState,
+};
+use crate::db::Database;
+use crate::metrics::Metrics;
+
+/// How long a room is kept around with zero live connections before its
+/// actor reaps it, so a brief disconnect (tab refresh) still allows a
+/// reconnect but an abandoned room doesn't leak forever.
+const IDLE_ROOM_TTL: Duration = Duration::from_secs(300);
+
+/// Commands a room's actor task understands. Each except `ReapIfIdle`
+/// carries a oneshot `reply`.
+enum RoomCommand {
+    Join {
+        player: Player,
+        reply: oneshot::Sender<Result<JoinOutcome, anyhow::Error>>,
+    },
+    Subscribe {
+        player_id: String,
+        channel: Sender<ServerResponse>,
+        reply: oneshot::Sender<Result<(ConnectionId, Room), anyhow::Error>>,
+    },
+    RemoveConnection {
+        player_id: String,
+        connection_id: ConnectionId,
+        reply: oneshot::Sender<Result<RemoveConnectionOutcome, anyhow::Error>>,
+    },
+    Disconnect {
+        player_id: String,
+        reply: oneshot::Sender<Result<DisconnectOutcome, anyhow::Error>>,
+    },
+    StatusUpdate {
+        user_id: String,
+        connection_id: ConnectionId,
+        ready_state: ReadyData,
+        reply: oneshot::Sender<Result<bool, anyhow::Error>>,
+    },
+    SetReadyState {
+        user_id: String,
+        connection_id: ConnectionId,
+        ready_state: ReadyData,
+        reply: oneshot::Sender<Result<(), anyhow::Error>>,
+    },
+    Chat {
+        player_id: String,
+        connection_id: ConnectionId,
+        message: String,
+        reply: oneshot::Sender<Result<(), anyhow::Error>>,
+    },
+    SetMedia {
+        player_id: String,
+        connection_id: ConnectionId,
+        media: Option<MediaItem>,
+        reply: oneshot::Sender<Result<(), anyhow::Error>>,
+    },
+    SetPlaylist {
+        player_id: String,
+        connection_id: ConnectionId,
+        playlist: Vec<MediaItem>,
+        reply: oneshot::Sender<Result<(), anyhow::Error>>,
+    },
+    DirectMessage {
+        from_player_id: String,
+        to_player_id: String,
+        message: String,
+        reply: oneshot::Sender<Result<(), anyhow::Error>>,
+    },
+    /// Fire-and-forget: torn down the room if it's still idle after the TTL.
+    ReapIfIdle,
+}
+
+pub struct JoinOutcome {
+    pub room: Room,
+    /// Whether a new member was actually added, vs. a no-op re-join.
+    pub newly_joined: bool,
+}
+
+pub struct RemoveConnectionOutcome {
+    pub room_is_empty: bool,
+    pub member_count: usize,
+    /// Whether this was the player's last live connection.
+    pub player_fully_disconnected: bool,
+}
+
+pub struct DisconnectOutcome {
+    pub room_is_empty: bool,
+}
+
+/// A handle to a room's actor task. Clones share one command channel, so
+/// every mutation and broadcast for a room is serialized inside that task.
+#[derive(Clone)]
+pub struct RoomHandle {
+    tx: mpsc::Sender<RoomCommand>,
+}
+
+impl RoomHandle {
+    pub fn spawn(
+        room: Room,
+        db: Arc<Database>,
+        rooms: Arc<DashMap<String, RoomHandle>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(run(room, rx, tx.clone(), db, rooms, metrics));
+        Self { tx }
+    }
+
+    pub async fn join(&self, player: Player) -> Result<JoinOutcome, anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(RoomCommand::Join { player, reply }).await?;
+        rx.await?
+    }
+
+    pub async fn subscribe(
+        &self,
+        player_id: String,
+        channel: Sender<ServerResponse>,
+    ) -> Result<(ConnectionId, Room), anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::Subscribe {
+                player_id,
+                channel,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn remove_connection(
+        &self,
+        player_id: String,
+        connection_id: ConnectionId,
+    ) -> Result<RemoveConnectionOutcome, anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::RemoveConnection {
+                player_id,
+                connection_id,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn disconnect(&self, player_id: String) -> Result<DisconnectOutcome, anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::Disconnect { player_id, reply })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn status_update(
+        &self,
+        user_id: String,
+        connection_id: ConnectionId,
+        ready_state: ReadyData,
+    ) -> Result<bool, anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::StatusUpdate {
+                user_id,
+                connection_id,
+                ready_state,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn set_ready_state(
+        &self,
+        user_id: String,
+        connection_id: ConnectionId,
+        ready_state: ReadyData,
+    ) -> Result<(), anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::SetReadyState {
+                user_id,
+                connection_id,
+                ready_state,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn chat(
+        &self,
+        player_id: String,
+        connection_id: ConnectionId,
+        message: String,
+    ) -> Result<(), anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::Chat {
+                player_id,
+                connection_id,
+                message,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn set_media(
+        &self,
+        player_id: String,
+        connection_id: ConnectionId,
+        media: Option<MediaItem>,
+    ) -> Result<(), anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::SetMedia {
+                player_id,
+                connection_id,
+                media,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn set_playlist(
+        &self,
+        player_id: String,
+        connection_id: ConnectionId,
+        playlist: Vec<MediaItem>,
+    ) -> Result<(), anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::SetPlaylist {
+                player_id,
+                connection_id,
+                playlist,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+
+    pub async fn direct_message(
+        &self,
+        from_player_id: String,
+        to_player_id: String,
+        message: String,
+    ) -> Result<(), anyhow::Error> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(RoomCommand::DirectMessage {
+                from_player_id,
+                to_player_id,
+                message,
+                reply,
+            })
+            .await?;
+        rx.await?
+    }
+}
+
+async fn run(
+    mut room: Room,
+    mut rx: mpsc::Receiver<RoomCommand>,
+    tx: mpsc::Sender<RoomCommand>,
+    db: Arc<Database>,
+    rooms: Arc<DashMap<String, RoomHandle>>,
+    metrics: Arc<Metrics>,
+) {
+    while let Some(command) = rx.recv().await {
+        match command {
+            RoomCommand::Join { player, reply } => {
+                let result = room
+                    .add_player(player.clone())
+                    .map(|newly_joined| JoinOutcome {
+                        room: room.clone(),
+                        newly_joined,
+                    });
+                if let Ok(outcome) = &result {
+                    if outcome.newly_joined {
+                        room.broadcast(ServerResponse::PlayerJoined(PlayerJoined {
+                            player: player.clone(),
+                            room: room.clone(),
+                        }))
+                        .await;
+                        room.broadcast(ServerResponse::ChatMessage(ChatMessage {
+                            message: format!("{} Joined", player.name),
+                            player,
+                            color: Some("#00FF00".into()),
+                        }))
+                        .await;
+                    }
+                }
+                let _ = reply.send(result);
+            }
+            RoomCommand::Subscribe {
+                player_id,
+                channel,
+                reply,
+            } => {
+                let result = room
+                    .set_player_channel(player_id.clone(), channel.clone())
+                    .and_then(|connection_id| {
+                        room.get_player(&player_id)
+                            .map(|lp| (connection_id, lp.player.clone()))
+                            .ok_or_else(|| anyhow::anyhow!("Player not found"))
+                    });
+                match result {
+                    Ok((connection_id, player)) => {
+                        room.broadcast(ServerResponse::PlayerConnected(PlayerConnected {
+                            player: player.clone(),
+                            room: room.clone(),
+                        }))
+                        .await;
+                        room.broadcast(ServerResponse::ChatMessage(ChatMessage {
+                            message: format!("{} Connected", player.name),
+                            player,
+                            color: Some("#00FF00".into()),
+                        }))
+                        .await;
+                        if let Err(_er) = channel
+                            .send(ServerResponse::ConnectionEstablished(ConnectionEstablished {
+                                connection_id: connection_id.0,
+                            }))
+                            .await
+                        {
+                            log::warn!("ERROR SENDING");
+                        }
+                        if let Err(_er) = channel
+                            .send(ServerResponse::MediaChanged(MediaChanged {
+                                room: room.clone(),
+                            }))
+                            .await
+                        {
+                            log::warn!("ERROR SENDING");
+                        }
+                        match db.take_pending_direct_messages(&room.id, &player_id).await {
+                            Ok(pending) => {
+                                for dm in pending {
+                                    if let Err(_er) =
+                                        channel.send(ServerResponse::DirectMessage(dm)).await
+                                    {
+                                        log::warn!("ERROR SENDING");
+                                    }
+                                }
+                            }
+                            Err(er) => {
+                                log::warn!("Could not load pending direct messages: {:#?}", er)
+                            }
+                        }
+                        let _ = reply.send(Ok((connection_id, room.clone())));
+                    }
+                    Err(er) => {
+                        let _ = reply.send(Err(er));
+                    }
+                }
+            }
+            RoomCommand::RemoveConnection {
+                player_id,
+                connection_id,
+                reply,
+            } => match room.remove_connection(&player_id, connection_id) {
+                Ok(fully_disconnected) => {
+                    let room_is_empty = room.is_empty();
+                    if fully_disconnected && !room_is_empty {
+                        if let Some(lp) = room.get_player(&player_id) {
+                            let player = lp.player.clone();
+                            room.broadcast(ServerResponse::PlayerLeft(PlayerLeft {
+                                player: player.clone(),
+                                room: room.clone(),
+                            }))
+                            .await;
+                            room.broadcast(ServerResponse::ChatMessage(ChatMessage {
+                                message: format!("{} Left", player.name),
+                                player,
+                                color: Some("#FF0000".into()),
+                            }))
+                            .await;
+                        }
+                    }
+                    let _ = reply.send(Ok(RemoveConnectionOutcome {
+                        room_is_empty,
+                        member_count: room.users.len(),
+                        player_fully_disconnected: fully_disconnected,
+                    }));
+                    if room_is_empty {
+                        return;
+                    }
+                    if room.is_idle() {
+                        let tx = tx.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(IDLE_ROOM_TTL).await;
+                            let _ = tx.send(RoomCommand::ReapIfIdle).await;
+                        });
+                    }
+                }
+                Err(er) => {
+                    let _ = reply.send(Err(er));
+                }
+            },
+            RoomCommand::Disconnect { player_id, reply } => match room.remove_player(&player_id) {
+                Ok(player) => {
+                    let room_is_empty = room.is_empty();
+                    room.broadcast(ServerResponse::PlayerRemoved(PlayerRemoved {
+                        player: player.clone(),
+                        room: room.clone(),
+                    }))
+                    .await;
+                    room.broadcast(ServerResponse::ChatMessage(ChatMessage {
+                        message: format!("{} Removed", player.name),
+                        player,
+                        color: Some("#FF0000".into()),
+                    }))
+                    .await;
+                    let _ = reply.send(Ok(DisconnectOutcome { room_is_empty }));
+                    if room_is_empty {
+                        return;
+                    }
+                }
+                Err(er) => {
+                    let _ = reply.send(Err(er));
+                }
+            },
+            RoomCommand::StatusUpdate {
+                user_id,
+                connection_id,
+                ready_state,
+                reply,
+            } => {
+                let user = match room.get_player_mut(&user_id) {
+                    Some(user) => user,
+                    None => {
+                        let _ = reply.send(Err(anyhow::anyhow!("User not found")));
+                        continue;
+                    }
+                };
+                user.state = UserState::Ready(ready_state.clone());
+                let should_broadcast = room.users.iter().any(|user1| {
+                    if let Some(state1) = user1.state.as_ready() {
+                        room.users.iter().any(|user2| {
+                            if let Some(state2) = user2.state.as_ready() {
+                                state1.playing != state2.playing
+                                    || state1.position_secs.abs_diff(state2.position_secs)
+                                        > room.delay_difference_secs
+                            } else {
+                                false
+                            }
+                        })
+                    } else {
+                        false
+                    }
+                });
+                if should_broadcast {
+                    room.broadcast_except(
+                        &user_id,
+                        connection_id,
+                        ServerResponse::StatusUpdate(ready_state),
+                    )
+                    .await;
+                }
+                let _ = reply.send(Ok(should_broadcast));
+            }
+            RoomCommand::SetReadyState {
+                user_id,
+                connection_id,
+                ready_state,
+                reply,
+            } => {
+                let user = match room.get_player_mut(&user_id) {
+                    Some(user) => user,
+                    None => {
+                        let _ = reply.send(Err(anyhow::anyhow!("User not found")));
+                        continue;
+                    }
+                };
+                user.state = UserState::Ready(ready_state.clone());
+                room.broadcast_except(
+                    &user_id,
+                    connection_id,
+                    ServerResponse::StatusUpdate(ready_state),
+                )
+                .await;
+                let _ = reply.send(Ok(()));
+            }
+            RoomCommand::Chat {
+                player_id,
+                connection_id,
+                message,
+                reply,
+            } => {
+                let player = match room.get_player(&player_id) {
+                    Some(lp) => lp.player.clone(),
+                    None => {
+                        let _ = reply.send(Err(anyhow::anyhow!("Player not in room")));
+                        continue;
+                    }
+                };
+                room.broadcast_except(
+                    &player_id,
+                    connection_id,
+                    ServerResponse::ChatMessage(ChatMessage {
+                        player,
+                        message,
+                        color: None,
+                    }),
+                )
+                .await;
+                let _ = reply.send(Ok(()));
+            }
+            RoomCommand::SetMedia {
+                player_id,
+                connection_id,
+                media,
+                reply,
+            } => {
+                room.set_media(media);
+                room.broadcast_except(
+                    &player_id,
+                    connection_id,
+                    ServerResponse::MediaChanged(MediaChanged { room: room.clone() }),
+                )
+                .await;
+                let _ = reply.send(Ok(()));
+            }
+            RoomCommand::SetPlaylist {
+                player_id,
+                connection_id,
+                playlist,
+                reply,
+            } => {
+                room.set_playlist(playlist);
+                room.broadcast_except(
+                    &player_id,
+                    connection_id,
+                    ServerResponse::MediaChanged(MediaChanged { room: room.clone() }),
+                )
+                .await;
+                let _ = reply.send(Ok(()));
+            }
+            RoomCommand::DirectMessage {
+                from_player_id,
+                to_player_id,
+                message,
+                reply,
+            } => {
+                let from = match room.get_player(&from_player_id) {
+                    Some(lp) => lp.player.clone(),
+                    None => {
+                        let _ = reply.send(Err(anyhow::anyhow!("Player not in room")));
+                        continue;
+                    }
+                };
+                if room.get_player(&to_player_id).is_none() {
+                    let _ = reply.send(Err(anyhow::anyhow!("Recipient not in room")));
+                    continue;
+                }
+
+                let dm = DirectMessage {
+                    dialog_id: dialog_id(&from_player_id, &to_player_id),
+                    from,
+                    to_player_id: to_player_id.clone(),
+                    message,
+                };
+
+                let delivered = room
+                    .send_to(&to_player_id, ServerResponse::DirectMessage(dm.clone()))
+                    .await;
+                if !delivered {
+                    if let Err(er) = db.queue_direct_message(&room.id, &dm).await {
+                        log::warn!("Could not persist direct message: {:#?}", er);
+                    }
+                }
+                room.send_to(&from_player_id, ServerResponse::DirectMessage(dm))
+                    .await;
+                let _ = reply.send(Ok(()));
+            }
+            RoomCommand::ReapIfIdle => {
+                if room.is_idle() {
+                    log::info!("Reaping idle room {:#?}", room.id);
+                    rooms.remove(&room.id);
+                    metrics.rooms_active.dec();
+                    metrics.players_active.sub(room.users.len() as i64);
+                    if let Err(er) = db.remove_room(&room.id).await {
+                        log::warn!("Could not remove room {:#?}", er);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -6,17 +6,16 @@ use futures::Stream;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::Receiver;
 
-use crate::data::ChatMessage;
+use crate::data::ConnectionId;
+use crate::db::Database;
+use crate::data::MediaItemInput;
 use crate::data::Player;
-use crate::data::PlayerConnected;
-use crate::data::PlayerJoined;
-use crate::data::PlayerLeft;
-use crate::data::PlayerRemoved;
 use crate::data::ReadyData;
 use crate::data::Room;
 use crate::data::ServerResponse;
 use crate::data::Storage;
-use crate::data::UserState;
+use crate::metrics::Metrics;
+use crate::room_actor::RoomHandle;
 use crate::utils::generate_rand_string;
 
 pub struct QueryRoot;
@@ -38,6 +37,7 @@ impl QueryRoot {
         room_id: String,
         is_playing: bool,
         position_secs: u64,
+        connection_id: u64,
     ) -> Result<ReadyData, async_graphql::Error> {
         let ready_state = ReadyData {
             playing: is_playing,
@@ -45,42 +45,22 @@ impl QueryRoot {
         };
 
         let data = ctx.data::<Storage>()?;
-
-        let rooms = &data.private_rooms;
-        let mut room = rooms
-            .get_mut(&room_id)
-            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
-        let user = room.get_player_mut(&user_id);
-        match user {
-            Some(user) => {
-                user.state = UserState::Ready(ready_state.clone());
-                {
-                    let should_broadcast = room.users.iter().any(|user1| {
-                        if let Some(userstate) = user1.state.as_ready() {
-                            room.users.iter().any(|user2| {
-                                if let Some(userstate2) = user2.state.as_ready() {
-                                    userstate.playing != userstate2.playing
-                                        || userstate
-                                            .position_secs
-                                            .abs_diff(userstate2.position_secs)
-                                            > room.delay_difference_secs
-                                } else {
-                                    false
-                                }
-                            })
-                        } else {
-                            false
-                        }
-                    });
-                    if should_broadcast {
-                        room.broadcast(ServerResponse::StatusUpdate(ready_state.clone()))
-                            .await;
-                    }
-                }
-                Ok(ready_state)
-            }
-            None => Err(anyhow::anyhow!("User not found").into()),
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        data.db
+            .update_ready_state(&room_id, &user_id, &ready_state)
+            .await?;
+        let did_broadcast = room_handle
+            .status_update(user_id, ConnectionId(connection_id), ready_state.clone())
+            .await?;
+        if did_broadcast {
+            data.metrics.status_updates_total.inc();
         }
+        Ok(ready_state)
     }
 
     pub async fn paused<'ctx>(
@@ -89,6 +69,7 @@ impl QueryRoot {
         user_id: String,
         room_id: String,
         position_secs: u64,
+        connection_id: u64,
     ) -> Result<ReadyData, async_graphql::Error> {
         let ready_state = ReadyData {
             playing: false,
@@ -96,23 +77,17 @@ impl QueryRoot {
         };
 
         let data = ctx.data::<Storage>()?;
-
-        let rooms = &data.private_rooms;
-        let mut room = rooms
-            .get_mut(&room_id)
-            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
-        let user = room.get_player_mut(&user_id);
-        match user {
-            Some(user) => {
-                user.state = UserState::Ready(ready_state.clone());
-                {
-                    room.broadcast(ServerResponse::StatusUpdate(ready_state.clone()))
-                        .await;
-                }
-                Ok(ready_state)
-            }
-            None => Err(anyhow::anyhow!("User not found").into()),
-        }
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        room_handle
+            .set_ready_state(user_id, ConnectionId(connection_id), ready_state.clone())
+            .await?;
+        data.metrics.status_updates_total.inc();
+        Ok(ready_state)
     }
 
     pub async fn resumed<'ctx>(
@@ -121,6 +96,7 @@ impl QueryRoot {
         user_id: String,
         room_id: String,
         position_secs: u64,
+        connection_id: u64,
     ) -> Result<ReadyData, async_graphql::Error> {
         let ready_state = ReadyData {
             playing: true,
@@ -128,23 +104,17 @@ impl QueryRoot {
         };
 
         let data = ctx.data::<Storage>()?;
-
-        let rooms = &data.private_rooms;
-        let mut room = rooms
-            .get_mut(&room_id)
-            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
-        let user = room.get_player_mut(&user_id);
-        match user {
-            Some(user) => {
-                user.state = UserState::Ready(ready_state.clone());
-                {
-                    room.broadcast(ServerResponse::StatusUpdate(ready_state.clone()))
-                        .await;
-                }
-                Ok(ready_state)
-            }
-            None => Err(anyhow::anyhow!("User not found").into()),
-        }
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        room_handle
+            .set_ready_state(user_id, ConnectionId(connection_id), ready_state.clone())
+            .await?;
+        data.metrics.status_updates_total.inc();
+        Ok(ready_state)
     }
 }
 
@@ -165,17 +135,19 @@ impl MutationRoot {
         if rooms.contains_key(&room_id) {
             Err("Cant create room".into())
         } else {
+            let player = Player {
+                id: user_id,
+                name: user_name,
+            };
+            data.db.create_room(&room_id, delay_difference_secs).await?;
+            data.db.add_membership(&room_id, &player).await?;
+            let room = Room::new(room_id.clone(), player, delay_difference_secs);
             rooms.insert(
                 room_id.clone(),
-                Room::new(
-                    room_id.clone(),
-                    Player {
-                        id: user_id,
-                        name: user_name,
-                    },
-                    delay_difference_secs,
-                ),
+                RoomHandle::spawn(room, data.db.clone(), rooms.clone(), data.metrics.clone()),
             );
+            data.metrics.rooms_active.inc();
+            data.metrics.players_active.inc();
             Ok(room_id)
         }
     }
@@ -192,29 +164,17 @@ impl MutationRoot {
             id: player_id,
             name: player_name,
         };
-        let room = {
-            let rooms = &data.private_rooms;
-
-            let mut room = rooms
-                .get_mut(&room_id)
-                .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
-
-            room.add_player(player.clone())?;
-            room.clone()
-        };
-
-        room.broadcast(ServerResponse::PlayerJoined(PlayerJoined {
-            player: player.clone(),
-
-            room: room.clone(),
-        }))
-        .await;
-        room.broadcast(ServerResponse::ChatMessage(ChatMessage {
-            message: format!("{} Joined", player.name),
-            player: player,
-            color: Some("#00FF00".into()),
-        }))
-        .await;
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        let outcome = room_handle.join(player.clone()).await?;
+        data.db.add_membership(&room_id, &player).await?;
+        if outcome.newly_joined {
+            data.metrics.players_active.inc();
+        }
         Ok(room_id)
     }
 
@@ -225,35 +185,24 @@ impl MutationRoot {
         room_id: String,
     ) -> Result<String, async_graphql::Error> {
         let data = ctx.data::<Storage>()?;
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        let outcome = room_handle.disconnect(player_id.clone()).await?;
+        if outcome.room_is_empty {
+            data.private_rooms.remove(&room_id);
+        }
 
-        let (room, player) = {
-            let rooms = &data.private_rooms;
-
-            let mut room = rooms
-                .get_mut(&room_id)
-                .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
-
-            let player = room.remove_player(&player_id)?;
-            if room.is_empty() {
-                rooms.remove(&room.id);
-            }
-
-            (room.clone(), player)
-        };
+        data.db.remove_membership(&room_id, &player_id).await?;
+        data.metrics.players_active.dec();
+        if outcome.room_is_empty {
+            data.db.remove_room(&room_id).await?;
+            data.metrics.rooms_active.dec();
+        }
 
-        room.clone()
-            .broadcast(ServerResponse::PlayerRemoved(PlayerRemoved {
-                player: player.clone(),
-
-                room: room.clone(),
-            }))
-            .await;
-        room.broadcast(ServerResponse::ChatMessage(ChatMessage {
-            message: format!("{} Removed", player.name),
-            player: player.clone(),
-            color: Some("#FF0000".into()),
-        }))
-        .await;
         Ok("Disconnected".into())
     }
 
@@ -263,29 +212,90 @@ impl MutationRoot {
         player_id: String,
         room_id: String,
         message: String,
+        connection_id: u64,
     ) -> Result<String, async_graphql::Error> {
         let data = ctx.data::<Storage>()?;
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        room_handle
+            .chat(player_id, ConnectionId(connection_id), message)
+            .await?;
+        data.metrics.chat_messages_total.inc();
+        Ok("Sucess".into())
+    }
 
-        let (room, player) = {
-            let rooms = &data.private_rooms;
-
-            let room = rooms
-                .get_mut(&room_id)
-                .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
+    pub async fn set_media<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        player_id: String,
+        room_id: String,
+        media: Option<MediaItemInput>,
+        connection_id: u64,
+    ) -> Result<String, async_graphql::Error> {
+        let data = ctx.data::<Storage>()?;
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        room_handle
+            .set_media(
+                player_id,
+                ConnectionId(connection_id),
+                media.map(Into::into),
+            )
+            .await?;
+        Ok("Sucess".into())
+    }
 
-            let player = room
-                .get_player(&player_id)
-                .ok_or("Player not in room")?
-                .clone();
-            (room.clone(), player.player)
-        };
+    pub async fn set_playlist<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        player_id: String,
+        room_id: String,
+        playlist: Vec<MediaItemInput>,
+        connection_id: u64,
+    ) -> Result<String, async_graphql::Error> {
+        let data = ctx.data::<Storage>()?;
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        room_handle
+            .set_playlist(
+                player_id,
+                ConnectionId(connection_id),
+                playlist.into_iter().map(Into::into).collect(),
+            )
+            .await?;
+        Ok("Sucess".into())
+    }
 
-        room.broadcast(ServerResponse::ChatMessage(ChatMessage {
-            player,
-            message,
-            color: None,
-        }))
-        .await;
+    pub async fn direct_message<'ctx>(
+        &self,
+        ctx: &Context<'_>,
+        player_id: String,
+        room_id: String,
+        to_player_id: String,
+        message: String,
+    ) -> Result<String, async_graphql::Error> {
+        let data = ctx.data::<Storage>()?;
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        room_handle
+            .direct_message(player_id, to_player_id, message)
+            .await?;
         Ok("Sucess".into())
     }
 }
@@ -304,37 +314,28 @@ impl Subscription {
         let (tx, rx) = channel::<ServerResponse>(2);
 
         let data = ctx.data::<Storage>()?;
-        let room = {
-            let rooms = &data.private_rooms;
-            let mut room = rooms
-                .get_mut(&room_id)
-                .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?;
-            room.set_player_channel(player_id.clone(), tx)?;
-            room.clone()
-        };
+        let room_handle = data
+            .private_rooms
+            .get(&room_id)
+            .ok_or_else(|| async_graphql::Error::from("Room does not exist"))?
+            .clone();
+
+        let (connection_id, room) = room_handle.subscribe(player_id.clone(), tx).await?;
         let player = room
             .get_player(&player_id)
             .ok_or("Player not found ")?
             .clone()
             .player;
-        room.clone()
-            .broadcast(ServerResponse::PlayerConnected(PlayerConnected {
-                player: player.clone(),
-
-                room: room.clone(),
-            }))
-            .await;
-        room.broadcast(ServerResponse::ChatMessage(ChatMessage {
-            message: format!("{} Connected", player.name),
-            player: player.clone(),
-            color: Some("#00FF00".into()),
-        }))
-        .await;
+
         let player_dis = PlayerDisconnected {
             player,
+            connection_id,
             receiver_stream: rx,
-            rooms: ctx.data::<Storage>()?.private_rooms.clone(),
+            rooms: data.private_rooms.clone(),
+            room_handle,
             room_id,
+            metrics: data.metrics.clone(),
+            db: data.db.clone(),
         };
         Ok(player_dis)
     }
@@ -342,61 +343,41 @@ impl Subscription {
 
 pub struct PlayerDisconnected {
     player: Player,
+    connection_id: ConnectionId,
     receiver_stream: Receiver<ServerResponse>,
-    rooms: Arc<DashMap<String, Room>>,
+    rooms: Arc<DashMap<String, RoomHandle>>,
+    room_handle: RoomHandle,
     room_id: String,
+    metrics: Arc<Metrics>,
+    db: Arc<Database>,
 }
 
 impl Drop for PlayerDisconnected {
     fn drop(&mut self) {
         let rooms = self.rooms.clone();
+        let room_handle = self.room_handle.clone();
         let room_id = self.room_id.clone();
-        let player = self.player.clone();
+        let player_id = self.player.id.clone();
+        let connection_id = self.connection_id;
+        let metrics = self.metrics.clone();
+        let db = self.db.clone();
         tokio::spawn(async move {
+            log::info!("Removing connection for player {}", player_id);
+            match room_handle
+                .remove_connection(player_id.clone(), connection_id)
+                .await
             {
-                log::info!("Taking room to remove player {:#?}", player);
-                let rooms = &rooms;
-                log::info!("Removing player {:#?}", player);
-                let mut remove = false;
-                if let Some(mut room) = rooms.get_mut(&room_id) {
-                    if let Err(er) = room.disconnect_player(&player.id) {
-                        log::warn!("Could not remove player {:#?}", er)
-                    } else {
-                        log::info!("Player removed {:#?}", player);
-                    }
-                    if room.is_empty() {
-                        remove = true;
-                    } else {
-                        log::info!("Sending broadcast PlayerLeft {:#?}", player);
-
-                        log::info!("Updating Turn");
-
-                        log::info!("Turn Updated")
+                Ok(outcome) => {
+                    if outcome.room_is_empty {
+                        log::info!("Deleting room {:#?}", room_id);
+                        rooms.remove(&room_id);
+                        metrics.rooms_active.dec();
+                        if let Err(er) = db.remove_room(&room_id).await {
+                            log::warn!("Could not remove room {:#?}", er);
+                        }
                     }
                 }
-                if remove {
-                    log::info!("Deleting room {:#?}", room_id);
-
-                    rooms.remove(&room_id);
-                    log::info!("Deleted room {:#?}", room_id);
-                }
-            }
-            {
-                let rooms = &rooms;
-                if let Some(room) = rooms.get(&room_id) {
-                    room.clone()
-                        .broadcast(ServerResponse::PlayerLeft(PlayerLeft {
-                            player: player.clone(),
-                            room: room.clone(),
-                        }))
-                        .await;
-                    room.broadcast(ServerResponse::ChatMessage(ChatMessage {
-                        message: format!("{} Left", player.name),
-                        player: player.clone(),
-                        color: Some("#FF0000".into()),
-                    }))
-                    .await;
-                }
+                Err(er) => log::warn!("Could not remove connection {:#?}", er),
             }
         });
     }